@@ -2,16 +2,22 @@ use clap::{Parser, ValueEnum};
 use flate2::{write::GzEncoder, Compression};
 use indicatif::ProgressBar;
 use std::{
-    fs::{remove_file, File},
-    io::{prelude::*, BufReader, Result},
-    net::TcpListener,
-    path::PathBuf,
+    fs::File,
+    io::{prelude::*, BufReader, BufWriter, Cursor, ErrorKind, Result, SeekFrom},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
     process,
     str::FromStr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
 };
 use tar::Builder;
 use walkdir::WalkDir;
-use zip::{write::FileOptions, CompressionMethod::Zstd, ZipWriter};
+use zip::{read::ZipArchive, write::FileOptions, CompressionMethod::Zstd, ZipWriter};
 
 /// Send any number of files/directories over a local network quickly
 #[derive(Parser)]
@@ -38,6 +44,23 @@ struct Cli {
 
     #[arg(short, long, default_value_t = 7878)]
     port: u16,
+
+    /// Number of successful transfers to serve before exiting, 0 for unlimited
+    #[arg(short = 'n', long, default_value_t = 1)]
+    clients: u32,
+
+    /// Serve an HTML index of the given directory instead of archiving it
+    #[arg(long)]
+    browse: bool,
+
+    /// Require this shared secret (as a path segment or Authorization header)
+    /// before serving or accepting anything
+    #[arg(long)]
+    token: Option<String>,
+
+    /// Number of worker threads to use when compressing an archive
+    #[arg(long, default_value_t = 1)]
+    threads: usize,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
@@ -63,182 +86,430 @@ fn main() -> Result<()> {
 
     // receive mode response
     let mut headers: Vec<String> = Vec::new();
-    headers.push(OK_200.to_string());
-    let mut f_name: Option<String> = None;
-    let mut is_archive = false;
 
     // send mode response
-    if !cli.recv {
-        let (_f_name, _is_archive) = prepare_file(cli.enc, cli.paths);
-        f_name = Some(_f_name.clone());
-        is_archive = _is_archive;
-        headers.push("Content-Type: application/octet-stream".to_string());
-        headers.push(format!(
-            "Content-Disposition: attachment; filename=\"{_f_name}\""
-        ));
-
-        println!("\nServing {} at http://{addr}", _f_name);
+    let target = if !cli.recv {
+        let target = if cli.browse {
+            prepare_browse(cli.paths)
+        } else {
+            prepare_file(cli.enc, cli.paths)
+        };
+        println!("\nServing {} at http://{addr}", target.display_name());
+        Some(target)
     } else {
         println!("\nWaiting to receive at http://{addr}");
-    }
+        None
+    };
 
-    // terminate headers
+    // terminate headers (only used by the recv-mode GET / page below)
     headers.push("\r\n".to_string());
 
-    for stream in listener.incoming() {
-        let mut stream = stream?;
-        let mut request = BufReader::new(&mut stream);
-        let mut start_line = String::new();
-        request.read_line(&mut start_line)?;
+    let target = Arc::new(target);
+    let headers = Arc::new(headers);
+    let completed = Arc::new(AtomicU32::new(0));
+    let mut workers = Vec::new();
+
+    // non-blocking, so the loop notices `--clients` completed transfers
+    // without waiting for yet another connection to arrive
+    listener.set_nonblocking(true)?;
+    loop {
+        if cli.clients != 0 && completed.load(Ordering::SeqCst) >= cli.clients {
+            break;
+        }
+
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(50));
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        stream.set_nonblocking(false)?;
+        let recv = cli.recv;
+        let target = Arc::clone(&target);
+        let headers = Arc::clone(&headers);
+        let completed = Arc::clone(&completed);
+        let token = cli.token.clone();
+        let threads = cli.threads;
+
+        workers.push(thread::spawn(move || {
+            let config = HandlerConfig {
+                recv,
+                target: &target,
+                headers: &headers,
+                send_html,
+                received_html,
+                token: token.as_deref(),
+                threads,
+            };
+            match handle_connection(stream, &config) {
+                Ok(true) => {
+                    completed.fetch_add(1, Ordering::SeqCst);
+                }
+                Ok(false) => {}
+                Err(e) => println!("Error: {e}"),
+            }
+        }));
+    }
+
+    for worker in workers {
+        let _ = worker.join();
+    }
+
+    Ok(())
+}
+
+// Everything a connection-handling worker thread needs besides the
+// `TcpStream` itself, bundled up so `handle_connection` doesn't grow a new
+// parameter every time a flag needs threading through to it. Every field
+// is a reference or a small Copy type, so the whole struct is Copy too.
+#[derive(Clone, Copy)]
+struct HandlerConfig<'a> {
+    recv: bool,
+    target: &'a Option<ServeTarget>,
+    headers: &'a [String],
+    send_html: &'a [u8],
+    received_html: &'a [u8],
+    token: Option<&'a str>,
+    threads: usize,
+}
+
+// Handles a single connection end-to-end, returning whether it completed
+// a real transfer (a served GET or a received POST). The accept loop in
+// `main` counts these towards `--clients`; error replies and the
+// receive-mode upload page don't count.
+fn handle_connection(mut stream: TcpStream, config: &HandlerConfig) -> Result<bool> {
+    let HandlerConfig {
+        recv,
+        target,
+        headers,
+        send_html,
+        received_html,
+        token,
+        threads,
+    } = *config;
+
+    let mut request = BufReader::new(&mut stream);
+    let mut start_line = String::new();
+    request.read_line(&mut start_line)?;
+
+    let mut start_parts = start_line.split_whitespace();
+    let method = start_parts.next().unwrap_or("");
+    let req_path = start_parts.next().unwrap_or("/").to_string();
 
-        if start_line == "GET / HTTP/1.1\r\n" {
+    if method == "GET" {
+        // parse request headers, take_while stops on the first empty line
+        let req_headers: Vec<String> = request
+            .by_ref()
+            .lines()
+            .map(|result| result.unwrap())
+            .take_while(|line| !line.is_empty())
+            .collect();
+
+        let req_path = match authorize(token, &req_path, &req_headers) {
+            Some(req_path) => req_path,
+            None => return stream.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n").map(|_| false),
+        };
+
+        if recv {
+            if req_path != "/" {
+                return respond_404(&mut stream).map(|_| false);
+            }
             // headers and body are already prepared depending on send/recv mode
+            stream.write_all(format!("{OK_200}\r\n").as_bytes())?;
             stream.write_all(&headers.join("\r\n").into_bytes())?;
+            stream.write_all(send_html)?;
+            return Ok(false);
+        }
+
+        match target.as_ref().unwrap() {
+            ServeTarget::Browse { root } => {
+                return serve_browse(&mut stream, root, &req_path);
+            }
+            ServeTarget::File(f_path) => {
+                if req_path != "/" {
+                    return respond_404(&mut stream).map(|_| false);
+                }
+                let filesize = File::open(f_path)?.metadata()?.len();
+
+                let range = req_headers
+                    .iter()
+                    .find(|line| line.to_lowercase().starts_with("range:"))
+                    .and_then(|line| {
+                        let value = line.splitn(2, ':').last().unwrap().trim();
+                        parse_range(value, filesize)
+                    });
 
-            if cli.recv {
-                stream.write_all(send_html)?;
-            } else {
-                let f = File::open(f_name.as_ref().unwrap())?;
+                let f = File::open(f_path)?;
                 let mut f_buf = BufReader::with_capacity(BUF_SIZE, f);
 
+                let file_headers = [
+                    "Accept-Ranges: bytes".to_string(),
+                    "Content-Type: application/octet-stream".to_string(),
+                    format!(
+                        "Content-Disposition: attachment; filename=\"{}\"",
+                        f_path.to_str().unwrap()
+                    ),
+                    "\r\n".to_string(),
+                ];
+
+                let mut remaining = match range {
+                    Some(RangeRequest::Unsatisfiable) => {
+                        stream.write_all(b"HTTP/1.1 416 Range Not Satisfiable\r\n")?;
+                        stream.write_all(
+                            format!("Content-Range: bytes */{filesize}\r\n\r\n").as_bytes(),
+                        )?;
+                        return Ok(false);
+                    }
+                    Some(RangeRequest::Satisfiable(start, end)) => {
+                        let content_length = end - start + 1;
+                        stream.write_all(b"HTTP/1.1 206 Partial Content\r\n")?;
+                        stream.write_all(
+                            format!("Content-Range: bytes {start}-{end}/{filesize}\r\n")
+                                .as_bytes(),
+                        )?;
+                        stream
+                            .write_all(format!("Content-Length: {content_length}\r\n").as_bytes())?;
+                        stream.write_all(&file_headers.join("\r\n").into_bytes())?;
+
+                        f_buf.seek(SeekFrom::Start(start))?;
+                        content_length
+                    }
+                    None => {
+                        stream.write_all(format!("{OK_200}\r\n").as_bytes())?;
+                        stream.write_all(&file_headers.join("\r\n").into_bytes())?;
+                        filesize
+                    }
+                };
+
                 // TODO: verify if this is needed?
                 // buffered write so we don't store the entire file in memory
                 loop {
+                    if remaining == 0 {
+                        break;
+                    }
                     let buf = f_buf.fill_buf()?;
-                    let length = buf.len();
+                    let length = (buf.len() as u64).min(remaining) as usize;
                     if length == 0 {
                         break;
                     }
-                    stream.write_all(buf)?;
+                    stream.write_all(&buf[..length])?;
                     f_buf.consume(length);
+                    remaining -= length as u64;
                 }
-
-                // stop after sending file
-                break;
             }
-        } else if start_line == "POST / HTTP/1.1\r\n" {
-            // parse request headers, take_while stops on the first empty line
-            // this consumes the request!
-            let req_headers: Vec<String> = request
-                .by_ref()
-                .lines()
-                .map(|result| result.unwrap())
-                .take_while(|line| !line.is_empty())
-                .collect();
-
-            // get body metadata
-            let mut content_length: usize = 0; // number of octets (8 bits, u8)
-            let mut boundary = "";
-            for line in &req_headers {
-                if line.starts_with("Content-Length") {
-                    content_length = line.split(":").last().unwrap().trim().parse().unwrap();
-                }
-                if line.starts_with("Content-Type") {
-                    boundary = line
-                        .split(";")
-                        .last()
-                        .unwrap()
-                        .trim()
-                        .split("=")
-                        .last()
-                        .unwrap();
+            ServeTarget::Archive {
+                encoding,
+                paths,
+                display_name,
+            } => {
+                if req_path != "/" {
+                    return respond_404(&mut stream).map(|_| false);
                 }
-            }
-            if content_length == 0 {
-                break;
-            }
 
-            /*
-            the response looks like this:
-            ------WebKitFormBoundarymYhM14kHZ7UuBLfN
-            Content-Disposition: form-data; name="upload-file"; filename="..."
-            Content-Type: application/octet-stream
-
-            <file contents>
-            ------WebKitFormBoundarymYhM14kHZ7UuBLfN--
-
-            boundary start: --boundarystring
-            boundary end: --boundarystring--
-            content-length includes the the boundaries
-            */
-
-            let mut filename = String::new();
-            let mut bytes_consumed = 0;
-            loop {
-                // use this instead of map because we need to handle exact
-                // amounts of bytes, and this is more granular
-                let mut line = String::new();
-                bytes_consumed += request.read_line(&mut line)?;
-                if line.starts_with("Content-Disposition") {
-                    filename = line
-                        .split(";")
-                        .last()
-                        .unwrap()
-                        .trim()
-                        .split("=")
-                        .last()
-                        .unwrap()
-                        .trim_matches('"')
-                        .to_string();
+                // walked up front (for both encodings) so an empty archive
+                // turns into a normal error response instead of a 200 with
+                // a truncated/misleading body, for tgz same as zip
+                let entries = collect_entries(paths);
+                if entries.is_empty() {
+                    println!("Error: Archive does not contain any files");
+                    return respond_500(&mut stream).map(|_| false);
                 }
-                // first empty line starts file content
-                if line == "\r\n" {
-                    break;
+
+                match encoding {
+                    Encoding::Tgz => {
+                        // final size isn't known until the archive is fully streamed
+                        write_archive_headers(&mut stream, display_name)?;
+                        let mut out = BufWriter::with_capacity(BUF_SIZE, &mut stream);
+                        archive_tgz(&mut out, entries, threads)?;
+                        out.flush()?;
+                    }
+                    Encoding::Zip => {
+                        let buf = archive_zip(entries, threads)?;
+                        write_archive_headers(&mut stream, display_name)?;
+                        stream.write_all(&buf)?;
+                    }
                 }
             }
+        }
 
-            /* this is different to Vec::with_capacity()
-            https://stackoverflow.com/questions/68979882/readread-exact-does-not-fill-buffer
+        // file sent
+        Ok(true)
+    } else if method == "POST" {
+        // parse request headers, take_while stops on the first empty line
+        // this consumes the request!
+        let req_headers: Vec<String> = request
+            .by_ref()
+            .lines()
+            .map(|result| result.unwrap())
+            .take_while(|line| !line.is_empty())
+            .collect();
 
-            - 2 bytes \r\n before boundary line
-            - 2 bytes \r\n after boundary line
-            - 4 bytes for -- wrapping boundary line on either side
-            */
+        let req_path = match authorize(token, &req_path, &req_headers) {
+            Some(req_path) => req_path,
+            None => return stream.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n").map(|_| false),
+        };
+        if req_path != "/" {
+            return Ok(false);
+        }
 
-            let filesize = content_length - bytes_consumed - boundary.len() - 8;
-            let mut buffer: Vec<u8> = vec![0; filesize];
-            request.read_exact(&mut buffer)?;
+        // get body metadata
+        let mut content_length: usize = 0; // number of octets (8 bits, u8)
+        let mut boundary = "";
+        for line in &req_headers {
+            if line.starts_with("Content-Length") {
+                content_length = line.split(":").last().unwrap().trim().parse().unwrap();
+            }
+            if line.starts_with("Content-Type") {
+                boundary = line
+                    .split(";")
+                    .last()
+                    .unwrap()
+                    .trim()
+                    .split("=")
+                    .last()
+                    .unwrap();
+            }
+        }
+        if content_length == 0 {
+            return Ok(false);
+        }
+
+        /*
+        the response looks like this:
+        ------WebKitFormBoundarymYhM14kHZ7UuBLfN
+        Content-Disposition: form-data; name="upload-file"; filename="..."
+        Content-Type: application/octet-stream
 
-            // write file
-            let f_path = PathBuf::from_str(filename.as_str()).unwrap();
-            if f_path.exists() {
-                println!("Error: {:?} already exists", f_path);
-            } else {
-                let mut f = File::create(&f_path)?;
-                f.write_all(&buffer)?;
-                f.flush()?;
+        <file contents>
+        ------WebKitFormBoundarymYhM14kHZ7UuBLfN--
 
-                println!("\n{:?} received", f_path);
+        boundary start: --boundarystring
+        boundary end: --boundarystring--
+        content-length includes the the boundaries
+        */
+
+        let mut filename = String::new();
+        let mut bytes_consumed = 0;
+        loop {
+            // use this instead of map because we need to handle exact
+            // amounts of bytes, and this is more granular
+            let mut line = String::new();
+            bytes_consumed += request.read_line(&mut line)?;
+            if line.starts_with("Content-Disposition") {
+                filename = line
+                    .split(";")
+                    .last()
+                    .unwrap()
+                    .trim()
+                    .split("=")
+                    .last()
+                    .unwrap()
+                    .trim_matches('"')
+                    .to_string();
+            }
+            // first empty line starts file content
+            if line == "\r\n" {
+                break;
             }
+        }
 
-            // html templating at its finest :)
-            let mut vec_received_html = received_html.to_vec();
+        /* this is different to Vec::with_capacity()
+        https://stackoverflow.com/questions/68979882/readread-exact-does-not-fill-buffer
 
-            replace(&mut vec_received_html, b"{filename}", filename.as_bytes());
-            replace(
-                &mut vec_received_html,
-                b"{bytes}",
-                filesize.to_string().as_bytes(),
-            );
+        - 2 bytes \r\n before boundary line
+        - 2 bytes \r\n after boundary line
+        - 4 bytes for -- wrapping boundary line on either side
+        */
 
-            stream.write_all(OK_200.as_bytes())?;
-            stream.write_all(b"\r\n\r\n")?;
-            stream.write_all(&vec_received_html)?;
+        let filesize = content_length - bytes_consumed - boundary.len() - 8;
+        let mut buffer: Vec<u8> = vec![0; filesize];
+        request.read_exact(&mut buffer)?;
 
-            break;
+        // strip directory components and reject `..` before trusting the
+        // client-supplied name, since it otherwise points wherever it wants
+        let filename = match sanitize_filename(&filename) {
+            Some(name) => name,
+            None => {
+                println!("Error: rejecting unsafe upload filename {:?}", filename);
+                stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")?;
+                return Ok(false);
+            }
+        };
+
+        // write file
+        let f_path = PathBuf::from_str(filename.as_str()).unwrap();
+        if f_path.exists() {
+            println!("Error: {:?} already exists", f_path);
+        } else {
+            let mut f = File::create(&f_path)?;
+            f.write_all(&buffer)?;
+            f.flush()?;
+
+            println!("\n{:?} received", f_path);
         }
-    }
 
-    // cleanup
-    if is_archive {
-        remove_file(f_name.unwrap())?;
-    }
+        // html templating at its finest :)
+        let mut vec_received_html = received_html.to_vec();
 
-    Ok(())
+        replace(&mut vec_received_html, b"{filename}", filename.as_bytes());
+        replace(
+            &mut vec_received_html,
+            b"{bytes}",
+            filesize.to_string().as_bytes(),
+        );
+
+        stream.write_all(OK_200.as_bytes())?;
+        stream.write_all(b"\r\n\r\n")?;
+        stream.write_all(&vec_received_html)?;
+
+        Ok(true)
+    } else {
+        Ok(false)
+    }
 }
 
 // ---
 
+enum RangeRequest {
+    Satisfiable(u64, u64),
+    Unsatisfiable,
+}
+
+// parses the value of a `Range` header, supporting the `bytes=N-`, `bytes=N-M`
+// and `bytes=-S` forms from https://httpwg.org/specs/rfc7233.html#header.range
+fn parse_range(value: &str, filesize: u64) -> Option<RangeRequest> {
+    let spec = value.strip_prefix("bytes=")?;
+    let (start_str, end_str) = spec.split_once('-')?;
+
+    let (start, end) = if start_str.is_empty() {
+        // bytes=-S, last S bytes of the file
+        let suffix_len: u64 = end_str.parse().ok()?;
+        let start = filesize.saturating_sub(suffix_len);
+        (start, filesize.saturating_sub(1))
+    } else {
+        let start: u64 = start_str.parse().ok()?;
+        let end = if end_str.is_empty() {
+            filesize.saturating_sub(1)
+        } else {
+            end_str.parse().ok()?
+        };
+        (start, end)
+    };
+
+    if start >= filesize {
+        return Some(RangeRequest::Unsatisfiable);
+    }
+
+    let end = end.min(filesize - 1);
+    if start > end {
+        return Some(RangeRequest::Unsatisfiable);
+    }
+
+    Some(RangeRequest::Satisfiable(start, end))
+}
+
 // https://stackoverflow.com/questions/54150353/how-to-find-and-replace-every-matching-slice-of-bytes-with-another-slice
 fn replace(source: &mut Vec<u8>, from: &[u8], to: &[u8]) {
     let from_len = from.len();
@@ -255,18 +526,32 @@ fn replace(source: &mut Vec<u8>, from: &[u8], to: &[u8]) {
     }
 }
 
-fn prepare_file(encoding: Encoding, paths: Vec<PathBuf>) -> (String, bool) {
-    let temp_file = match encoding {
-        Encoding::Tgz => {
-            format!("{TEMP_FILE}.tar.gz")
-        }
-        Encoding::Zip => {
-            format!("{TEMP_FILE}.zip")
-        }
-    };
+/// What a send-mode GET request serves: a single file on disk (handled
+/// with Range support), an archive built on the fly straight into the
+/// response body, or an HTML index of a browsable directory tree.
+enum ServeTarget {
+    File(PathBuf),
+    Archive {
+        encoding: Encoding,
+        paths: Vec<PathBuf>,
+        display_name: String,
+    },
+    Browse {
+        root: PathBuf,
+    },
+}
 
-    let mut is_archive = false;
+impl ServeTarget {
+    fn display_name(&self) -> &str {
+        match self {
+            ServeTarget::File(path) => path.to_str().unwrap(),
+            ServeTarget::Archive { display_name, .. } => display_name,
+            ServeTarget::Browse { root } => root.to_str().unwrap(),
+        }
+    }
+}
 
+fn prepare_file(encoding: Encoding, paths: Vec<PathBuf>) -> ServeTarget {
     // if first path doesn't exist, exit
     let p_0 = match paths.get(0) {
         None => {
@@ -276,126 +561,464 @@ fn prepare_file(encoding: Encoding, paths: Vec<PathBuf>) -> (String, bool) {
         Some(p) => p,
     };
 
-    let f_name = if paths.len() == 1 && p_0.is_file() {
+    if paths.len() == 1 && p_0.is_file() {
         // 1 file only
-        String::from(p_0.to_str().unwrap())
+        ServeTarget::File(p_0.clone())
     } else if paths.len() > 1 || p_0.is_dir() {
-        // multiple files/dirs
-        println!("Adding files/dirs to {temp_file}...");
-        is_archive = archive(&temp_file, encoding, paths).unwrap();
-        println!("{temp_file} written successfully!");
+        // multiple files/dirs: archived lazily, straight into the response
+        // body, once a client actually asks for it
+        let display_name = match encoding {
+            Encoding::Tgz => format!("{TEMP_FILE}.tar.gz"),
+            Encoding::Zip => format!("{TEMP_FILE}.zip"),
+        };
 
-        temp_file
+        ServeTarget::Archive {
+            encoding,
+            paths,
+            display_name,
+        }
     } else {
         // first path is invalid
         println!("{:?} is not a valid path, exiting...", p_0);
         process::exit(-1);
-    };
+    }
+}
 
-    (f_name, is_archive)
+fn prepare_browse(paths: Vec<PathBuf>) -> ServeTarget {
+    match paths.as_slice() {
+        [p] if p.is_dir() => ServeTarget::Browse { root: p.clone() },
+        _ => {
+            println!("Error: --browse takes exactly one directory, exiting...");
+            process::exit(-1);
+        }
+    }
 }
 
 // ---
 
-fn archive(temp_file: &String, enc: Encoding, paths: Vec<PathBuf>) -> Result<bool> {
-    let mut has_files = false;
-    let f = File::create(temp_file)?;
-    let bar = ProgressBar::new(paths.len().try_into().unwrap());
+// Serves a `--browse` request: resolves `req_path` against `root`,
+// rejecting anything that escapes it, then either streams the file or
+// renders an HTML listing of the directory. Returns false when it answered
+// with a 404 instead.
+fn serve_browse(stream: &mut TcpStream, root: &Path, req_path: &str) -> Result<bool> {
+    let root = match root.canonicalize() {
+        Ok(root) => root,
+        Err(_) => return respond_404(stream).map(|_| false),
+    };
 
-    match enc {
-        Encoding::Tgz => {
-            let enc = GzEncoder::new(f, Compression::fast());
-            let mut tar = Builder::new(enc);
+    let relative = percent_decode(req_path.trim_start_matches('/'));
+    let requested = match root.join(relative).canonicalize() {
+        Ok(requested) => requested,
+        Err(_) => return respond_404(stream).map(|_| false),
+    };
 
-            for (i, path) in paths.iter().enumerate() {
-                let p = path.file_name().unwrap().to_str().unwrap();
+    // respond 404 rather than 403 here - a 403 would tell an unauthenticated
+    // client that a path outside `root` exists on the host, turning this
+    // into an existence oracle for arbitrary absolute paths
+    if !requested.starts_with(&root) {
+        return respond_404(stream).map(|_| false);
+    }
 
-                // add file to archive
-                if path.is_file() {
-                    let mut f = File::open(path)?;
-                    tar.append_file(p, &mut f)?;
-                    has_files = true
-                }
-                // add dir to archive with dirname as last path component
-                else if path.is_dir() {
-                    let dirname = format!("{p}-{i}");
-                    tar.append_dir_all(dirname, path).unwrap();
-                    has_files = true
-                }
-                // if neither, print error
-                else {
-                    println!("Error: {:?} is not a valid path", path);
-                }
+    if requested.is_dir() {
+        let body = render_listing(&root, &requested);
+        stream.write_all(format!("{OK_200}\r\n").as_bytes())?;
+        stream.write_all(b"Content-Type: text/html\r\n\r\n")?;
+        stream.write_all(body.as_bytes())?;
+        return Ok(true);
+    }
+
+    let filesize = requested.metadata()?.len();
+    let f = File::open(&requested)?;
+    let mut f_buf = BufReader::with_capacity(BUF_SIZE, f);
+
+    stream.write_all(format!("{OK_200}\r\n").as_bytes())?;
+    stream.write_all(format!("Content-Type: {}\r\n", guess_mime(&requested)).as_bytes())?;
+    stream.write_all(format!("Content-Length: {filesize}\r\n\r\n").as_bytes())?;
+
+    loop {
+        let buf = f_buf.fill_buf()?;
+        let length = buf.len();
+        if length == 0 {
+            break;
+        }
+        stream.write_all(buf)?;
+        f_buf.consume(length);
+    }
+
+    Ok(true)
+}
+
+fn respond_404(stream: &mut TcpStream) -> Result<()> {
+    stream.write_all(b"HTTP/1.1 404 Not Found\r\n\r\n")
+}
+
+fn respond_500(stream: &mut TcpStream) -> Result<()> {
+    stream.write_all(b"HTTP/1.1 500 Internal Server Error\r\n\r\n")
+}
+
+// Writes the status line and headers shared by both archive encodings.
+fn write_archive_headers(stream: &mut TcpStream, display_name: &str) -> Result<()> {
+    stream.write_all(format!("{OK_200}\r\n").as_bytes())?;
+    stream.write_all(b"Content-Type: application/octet-stream\r\n")?;
+    stream.write_all(
+        format!("Content-Disposition: attachment; filename=\"{display_name}\"\r\n").as_bytes(),
+    )?;
+    stream.write_all(b"Connection: close\r\n\r\n")
+}
+
+// When `--token` is set, requires the secret either as an `Authorization`
+// header or as the request path's first segment, and returns the path
+// with that segment stripped off so the rest of the routing logic doesn't
+// need to know about tokens at all. Returns None if unauthorized.
+fn authorize(token: Option<&str>, req_path: &str, req_headers: &[String]) -> Option<String> {
+    let token = match token {
+        None => return Some(req_path.to_string()),
+        Some(token) => token,
+    };
+
+    let authorized_by_header = req_headers
+        .iter()
+        .find(|line| line.to_lowercase().starts_with("authorization:"))
+        .map(|line| {
+            let value = line.split_once(':').map_or("", |(_, value)| value).trim();
+            value.strip_prefix("Bearer ").unwrap_or(value) == token
+        })
+        .unwrap_or(false);
+
+    if authorized_by_header {
+        return Some(req_path.to_string());
+    }
+
+    let prefix = format!("/{token}");
+    if req_path == prefix {
+        return Some("/".to_string());
+    }
+    req_path
+        .strip_prefix(&format!("{prefix}/"))
+        .map(|rest| format!("/{rest}"))
+}
+
+// Strips directory components and rejects `..` from a client-supplied
+// upload filename, since it's otherwise trusted verbatim into a `PathBuf`.
+fn sanitize_filename(name: &str) -> Option<String> {
+    let name = Path::new(name).file_name()?.to_str()?;
+    if name.is_empty() || name == ".." {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
+
+// Escapes text so it's safe to interpolate into an HTML response body or
+// (quoted) attribute value. File/directory names come straight from the
+// filesystem being browsed, which `--browse` treats as untrusted input.
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
 
-                bar.inc(1);
+// Lists the immediate children of `dir` (a sub-path of `root`) as a
+// minimal HTML index, linking each entry to its own browse/download path.
+fn render_listing(root: &Path, dir: &Path) -> String {
+    let rel_dir = dir.strip_prefix(root).unwrap();
+    let rel_dir_str = escape_html(&rel_dir.to_string_lossy());
+    let mut body = format!(
+        "<html><head><title>Index of /{0}</title></head><body><h1>Index of /{0}</h1><ul>\n",
+        rel_dir_str
+    );
+
+    if dir != root {
+        let parent_rel = rel_dir.parent().unwrap_or_else(|| Path::new(""));
+        body.push_str(&format!(
+            "<li><a href=\"/{}\">..</a></li>\n",
+            escape_html(&parent_rel.to_string_lossy())
+        ));
+    }
+
+    let mut entries: Vec<_> = WalkDir::new(dir)
+        .min_depth(1)
+        .max_depth(1)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .collect();
+    entries.sort_by_key(|entry| entry.file_name().to_os_string());
+
+    for entry in entries {
+        let name = escape_html(&entry.file_name().to_string_lossy());
+        let rel = entry.path().strip_prefix(root).unwrap();
+        let rel_str = escape_html(&rel.to_string_lossy());
+        let suffix = if entry.file_type().is_dir() { "/" } else { "" };
+        body.push_str(&format!(
+            "<li><a href=\"/{rel_str}{suffix}\">{name}{suffix}</a></li>\n",
+        ));
+    }
+
+    body.push_str("</ul></body></html>");
+    body
+}
+
+// percent-decodes a URL path segment (e.g. "%20" -> " "). Works on the raw
+// bytes throughout - slicing the source `&str` by `i + 1..i + 3` instead
+// would panic whenever those byte offsets land inside a multi-byte UTF-8
+// character (e.g. a literal "%" followed directly by one in the path).
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3).and_then(|pair| {
+                let hi = (pair[0] as char).to_digit(16)?;
+                let lo = (pair[1] as char).to_digit(16)?;
+                Some((hi * 16 + lo) as u8)
+            });
+            if let Some(byte) = hex {
+                out.push(byte);
+                i += 3;
+                continue;
             }
-            tar.finish()?;
         }
+        out.push(bytes[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// guesses a `Content-Type` from the file extension so browsers can
+// preview text/images inline instead of always downloading
+fn guess_mime(path: &Path) -> &'static str {
+    match path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("")
+        .to_lowercase()
+        .as_str()
+    {
+        "html" | "htm" => "text/html",
+        "txt" | "md" => "text/plain",
+        "css" => "text/css",
+        "js" => "application/javascript",
+        "json" => "application/json",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+// A single file or directory to add to an archive, with its destination
+// path inside the archive already resolved. Walking `paths` up front like
+// this lets the entries be handed out to a worker pool for compression
+// instead of being discovered (and compressed) one top-level path at a time.
+struct ArchiveEntry {
+    path: PathBuf,
+    name: String,
+    is_dir: bool,
+}
+
+fn collect_entries(paths: &[PathBuf]) -> Vec<ArchiveEntry> {
+    let mut entries = Vec::new();
+
+    for (i, path) in paths.iter().enumerate() {
+        let p = path.file_name().unwrap().to_str().unwrap();
+
+        // add file to archive
+        if path.is_file() {
+            entries.push(ArchiveEntry {
+                path: path.clone(),
+                name: p.to_string(),
+                is_dir: false,
+            });
+        }
+        // add dir to archive with dirname as last path component
+        else if path.is_dir() {
+            let dirname = PathBuf::from_str(format!("{p}-{i}").as_str()).unwrap();
 
-        Encoding::Zip => {
-            let mut zip = ZipWriter::new(f);
-            let options = FileOptions::default()
-                .compression_method(Zstd)
-                .unix_permissions(0o755);
-
-            // zip is confusing because you have to encode each file separately
-            for (i, path) in paths.iter().enumerate() {
-                let p = path.file_name().unwrap().to_str().unwrap();
-
-                // add file to archive
-                if path.is_file() {
-                    let f = File::open(path)?;
-                    zip_add_file(&mut zip, f, p, options)?;
-                    has_files = true;
-                }
-                // add dir to archive with dirname as last path component
-                else if path.is_dir() {
-                    let dirname = PathBuf::from_str(format!("{p}-{i}").as_str()).unwrap();
-
-                    let walkdir = WalkDir::new(path);
-                    for entry in walkdir.into_iter() {
-                        let e = entry?;
-                        let e_path = e.path();
-                        let e_child = e_path.strip_prefix(path.as_path()).unwrap();
-                        let e_new = dirname.join(e_child);
-
-                        // walkdir starts from the topmost directory, so e_child is empty
-                        match e_child.file_name() {
-                            Some(_) => {
-                                if e_path.is_file() {
-                                    let f = File::open(e_path)?;
-                                    zip_add_file(&mut zip, f, e_new.to_str().unwrap(), options)?;
-                                } else if e_path.is_dir() {
-                                    zip.add_directory(e_new.to_str().unwrap(), options)?;
-                                }
-                            }
-                            None => {
-                                // topmost dir
-                                zip.add_directory(dirname.to_str().unwrap(), options)?;
-                            }
-                        }
+            for entry in WalkDir::new(path).into_iter() {
+                let e = match entry {
+                    Ok(e) => e,
+                    Err(err) => {
+                        println!("Error: {err}");
+                        continue;
                     }
-                    has_files = true
-                }
-                // if neither, print error
-                else {
-                    println!("Error: {:?} is not a valid path", path);
-                }
+                };
+                let e_path = e.path();
+                let e_child = e_path.strip_prefix(path.as_path()).unwrap();
+
+                // walkdir starts from the topmost directory, so e_child is empty
+                let name = match e_child.file_name() {
+                    Some(_) => dirname.join(e_child),
+                    None => dirname.clone(),
+                };
+
+                entries.push(ArchiveEntry {
+                    path: e_path.to_path_buf(),
+                    name: name.to_str().unwrap().to_string(),
+                    is_dir: e_path.is_dir(),
+                });
             }
-            zip.finish()?;
+        }
+        // if neither, print error
+        else {
+            println!("Error: {:?} is not a valid path", path);
         }
     }
 
-    if !has_files {
-        println!("Error: Archive does not contain any files, exiting...");
-        remove_file(temp_file)?;
-        process::exit(-1);
+    entries
+}
+
+// Splits `entries` into up to `threads` contiguous, roughly even groups so
+// each worker compresses a stable slice and the output stays reproducible
+// once the groups are reassembled in order.
+fn chunk_entries(entries: Vec<ArchiveEntry>, threads: usize) -> Vec<Vec<ArchiveEntry>> {
+    let threads = threads.max(1);
+    if entries.is_empty() {
+        return vec![Vec::new()];
+    }
+    let chunk_size = entries.len().div_ceil(threads);
+
+    let mut chunks = Vec::new();
+    let mut rest = entries;
+    while !rest.is_empty() {
+        let tail = rest.split_off(chunk_size.min(rest.len()));
+        chunks.push(rest);
+        rest = tail;
+    }
+    chunks
+}
+
+// Compresses one chunk's entries into a standalone gzip member wrapping a
+// tar stream. Only the chunk that writes last should also write the tar
+// end-of-archive terminator (`into_inner` skips it for the rest), so the
+// members can simply be concatenated - gzip streams are valid when
+// concatenated back to back, same as `gzip`/`zcat` rely on for `.tar.gz`.
+fn compress_tgz_chunk(chunk: &[ArchiveEntry], bar: &ProgressBar, terminate: bool) -> Result<Vec<u8>> {
+    let enc = GzEncoder::new(Vec::new(), Compression::fast());
+    let mut tar = Builder::new(enc);
+
+    for entry in chunk {
+        if entry.is_dir {
+            tar.append_dir(&entry.name, &entry.path)?;
+        } else {
+            let mut f = File::open(&entry.path)?;
+            tar.append_file(&entry.name, &mut f)?;
+        }
+        bar.inc(1);
+    }
+
+    if terminate {
+        tar.finish()?;
+    }
+    tar.into_inner()?.finish()
+}
+
+// Streams a tar.gz archive of `entries` straight into `writer` (e.g. the
+// TCP stream itself) instead of spilling it to a temp file first. The
+// entries are split across `threads` workers and each worker's segment is
+// written out in order as soon as every worker has finished. `entries`
+// must be non-empty - the caller is expected to have already turned an
+// empty archive into an error response before any headers went out, the
+// same as `archive_zip`.
+fn archive_tgz<W: Write>(mut writer: W, entries: Vec<ArchiveEntry>, threads: usize) -> Result<()> {
+    let bar = ProgressBar::new(entries.len().try_into().unwrap());
+    let chunks = chunk_entries(entries, threads);
+    let last = chunks.len() - 1;
+
+    let segments = thread::scope(|scope| -> Result<Vec<Vec<u8>>> {
+        let handles: Vec<_> = chunks
+            .iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let bar = bar.clone();
+                scope.spawn(move || compress_tgz_chunk(chunk, &bar, i == last))
+            })
+            .collect();
+
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    })?;
+
+    for segment in segments {
+        writer.write_all(&segment)?;
+    }
+
+    Ok(())
+}
+
+// Compresses one chunk's entries into a standalone zip archive in memory,
+// so the main thread can merge the chunks without re-compressing anything.
+fn compress_zip_chunk(chunk: &[ArchiveEntry], bar: &ProgressBar, options: FileOptions) -> Result<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+
+    for entry in chunk {
+        if entry.is_dir {
+            zip.add_directory(&entry.name, options)?;
+        } else {
+            let f = File::open(&entry.path)?;
+            zip_add_file(&mut zip, f, &entry.name, options)?;
+        }
+        bar.inc(1);
+    }
+
+    Ok(zip.finish()?.into_inner())
+}
+
+// Builds a zip archive of `entries` in memory and returns its bytes.
+// `entries` must be non-empty - the caller is expected to have already
+// turned an empty archive into an error response before any headers went
+// out, the same as `archive_tgz`. Unlike tar.gz, the zip format
+// backpatches each entry's size/crc into its local header, which needs a
+// seekable writer - a `TcpStream` isn't one, so this assembles the
+// archive against a `Cursor<Vec<u8>>` and the caller writes the result
+// out once it's done, rather than streaming entry-by-entry.
+//
+// Each worker compresses its own chunk into an independent mini zip
+// archive, then the main thread copies each entry's already-compressed
+// bytes into the final archive with `raw_copy_file` so nothing gets
+// compressed twice, keeping entries in stable path order.
+fn archive_zip(entries: Vec<ArchiveEntry>, threads: usize) -> Result<Vec<u8>> {
+    let bar = ProgressBar::new(entries.len().try_into().unwrap());
+    let options = FileOptions::default()
+        .compression_method(Zstd)
+        .unix_permissions(0o755);
+    let chunks = chunk_entries(entries, threads);
+
+    let archives = thread::scope(|scope| -> Result<Vec<Vec<u8>>> {
+        let handles: Vec<_> = chunks
+            .iter()
+            .map(|chunk| {
+                let bar = bar.clone();
+                scope.spawn(move || compress_zip_chunk(chunk, &bar, options))
+            })
+            .collect();
+
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    })?;
+
+    let mut final_zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for archive in archives {
+        let mut reader = ZipArchive::new(Cursor::new(archive))?;
+        for i in 0..reader.len() {
+            let raw = reader.by_index_raw(i)?;
+            final_zip.raw_copy_file(raw)?;
+        }
     }
 
-    Ok(has_files)
+    Ok(final_zip.finish()?.into_inner())
 }
 
-fn zip_add_file(zip: &mut ZipWriter<File>, f: File, p: &str, options: FileOptions) -> Result<()> {
+fn zip_add_file<W: Write + std::io::Seek>(
+    zip: &mut ZipWriter<W>,
+    f: File,
+    p: &str,
+    options: FileOptions,
+) -> Result<()> {
     let mut f_reader = BufReader::new(f);
     zip.start_file(p, options)?;
     loop {